@@ -1,10 +1,11 @@
 use std::collections::HashMap;
 use std::error::Error;
 use std::fs;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
 
+use rayon::prelude::*;
 use sha1::Digest;
 use walkdir::WalkDir;
 
@@ -12,10 +13,91 @@ const INDEX_FILE: &str = ".osync";
 const IGNORE_FILE: &str = ".osyncignore";
 const SWAP_FILE: &str = ".osync.swp";
 
+/// Prefix for a swap file line that marks a remote path as mid-upload,
+/// rather than a completed `path:algorithm:hash` entry. Followed by
+/// `<remote_path>:<algorithm>:<hash>`, the digest of the local content
+/// being sent at the time.
+const RESUME_PREFIX: &str = "~resume~:";
+
+/// Size of the buffer used to stream a file through a hasher, instead of
+/// slurping the whole file into memory.
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The digest algorithm used to produce a file's hash.
+///
+/// Recorded alongside each hash so that an index computed with a different
+/// algorithm is recognized as such, rather than silently treated like a
+/// changed file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha1,
+    Blake3,
+}
+
+impl Algorithm {
+    fn as_str(self) -> &'static str {
+        match self {
+            Algorithm::Sha1 => "sha1",
+            Algorithm::Blake3 => "blake3",
+        }
+    }
+}
+
+impl std::str::FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Algorithm, String> {
+        match s {
+            "sha1" => Ok(Algorithm::Sha1),
+            "blake3" => Ok(Algorithm::Blake3),
+            _ => Err(format!("unknown hash algorithm: {}", s)),
+        }
+    }
+}
+
+/// The hash of a file, tagged with the algorithm that produced it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileDigest {
+    pub algorithm: Algorithm,
+    pub hash: String,
+}
+
+/// Stream `path` through the given algorithm's hasher in fixed-size chunks
+/// rather than reading the whole file into memory.
+fn hash_file<P: AsRef<Path>>(path: P, algorithm: Algorithm) -> Result<String, Box<dyn Error>> {
+    let mut file = File::open(path)?;
+    let mut buffer = [0u8; HASH_BUFFER_SIZE];
+
+    match algorithm {
+        Algorithm::Sha1 => {
+            let mut hasher = sha1::Sha1::new();
+            loop {
+                let read = file.read(&mut buffer)?;
+                if read == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..read]);
+            }
+            Ok(format!("{:x}", hasher.finalize()))
+        }
+        Algorithm::Blake3 => {
+            let mut hasher = blake3::Hasher::new();
+            loop {
+                let read = file.read(&mut buffer)?;
+                if read == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..read]);
+            }
+            Ok(hasher.finalize().to_hex().to_string())
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Index {
     directory: PathBuf,
-    files: HashMap<String, String>,
+    files: HashMap<String, FileDigest>,
 }
 
 impl Index {
@@ -27,12 +109,32 @@ impl Index {
     }
 
     fn from_file<P: AsRef<Path>>(file: P) -> Result<Index, Box<dyn Error>> {
-        let mut files: HashMap<String, String> = HashMap::new();
+        let mut files: HashMap<String, FileDigest> = HashMap::new();
         let buf = BufReader::new(File::open(file)?);
         for line in buf.lines() {
             let line = line.unwrap();
-            let parts: Vec<&str> = line.split(':').collect();
-            files.insert(parts[0].to_string(), parts[1].to_string());
+
+            // resume markers are handled separately by `resume_marker`
+            if line.starts_with(RESUME_PREFIX) {
+                continue;
+            }
+
+            let parts: Vec<&str> = line.splitn(3, ':').collect();
+
+            // older indexes only recorded "path:hash" (implicitly SHA-1)
+            let (path, algorithm, hash) = if parts.len() == 3 {
+                (parts[0], parts[1].parse().unwrap_or(Algorithm::Sha1), parts[2])
+            } else {
+                (parts[0], Algorithm::Sha1, parts[1])
+            };
+
+            files.insert(
+                path.to_string(),
+                FileDigest {
+                    algorithm,
+                    hash: hash.to_string(),
+                },
+            );
         }
 
         Ok(Index {
@@ -67,8 +169,19 @@ impl Index {
         Ok((index, resumed_files))
     }
 
-    /// Compute the index for given directory.
+    /// Compute the index for given directory, hashing files with SHA-1.
     pub fn compute<P: AsRef<Path>>(directory: P) -> Result<(Index, usize), Box<dyn Error>> {
+        Self::compute_with_algorithm(directory, Algorithm::Sha1)
+    }
+
+    /// Compute the index for given directory, hashing files in parallel with
+    /// the given algorithm. Each file is streamed through the hasher in
+    /// fixed-size chunks rather than read into memory whole, which matters
+    /// once the tree holds a LOT of files.
+    pub fn compute_with_algorithm<P: AsRef<Path>>(
+        directory: P,
+        algorithm: Algorithm,
+    ) -> Result<(Index, usize), Box<dyn Error>> {
         // try to load .osyncignore file
         let mut ignored_files: HashMap<String, bool> = HashMap::new();
         if let Ok(file) = File::open(directory.as_ref().join(IGNORE_FILE)) {
@@ -83,23 +196,30 @@ impl Index {
         ignored_files.insert(IGNORE_FILE.to_string(), true);
         ignored_files.insert(SWAP_FILE.to_string(), true);
 
-        let mut files: HashMap<String, String> = HashMap::new();
-        for entry in WalkDir::new(&directory).into_iter().filter_map(|e| e.ok()) {
-            let local_path = entry.path().strip_prefix(&directory)?;
-            let metadata = entry.metadata().unwrap();
+        let entries: Vec<_> = WalkDir::new(&directory)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .collect();
+
+        let files: HashMap<String, FileDigest> = entries
+            .into_par_iter()
+            .filter_map(|entry| {
+                let local_path = entry.path().strip_prefix(&directory).unwrap();
+                let metadata = entry.metadata().unwrap();
 
-            if metadata.is_file() && !ignored_files.contains_key(local_path.to_str().unwrap()) {
-                let bytes = fs::read(entry.path()).expect("unable to read file");
+                if !metadata.is_file() || ignored_files.contains_key(local_path.to_str().unwrap())
+                {
+                    return None;
+                }
 
-                let mut hasher = sha1::Sha1::new();
-                hasher.update(bytes);
+                let hash = hash_file(entry.path(), algorithm).expect("unable to read file");
 
-                files.insert(
+                Some((
                     local_path.to_str().unwrap().to_string(),
-                    format!("{:x}", hasher.finalize()),
-                );
-            }
-        }
+                    FileDigest { algorithm, hash },
+                ))
+            })
+            .collect();
 
         Ok((
             Index {
@@ -116,8 +236,8 @@ impl Index {
 
         // create file content
         let mut content = String::new();
-        for (path, hash) in self.files.iter() {
-            content += format!("{}:{}\n", path, hash).as_str();
+        for (path, digest) in self.files.iter() {
+            content += format!("{}:{}:{}\n", path, digest.algorithm.as_str(), digest.hash).as_str();
         }
 
         file.write_all(content.as_bytes()).map_err(|e| e.into())
@@ -129,8 +249,25 @@ impl Index {
         let mut changed_files: Vec<String> = Vec::new();
         let mut deleted_files: Vec<String> = Vec::new();
 
-        for (path, hash) in &b.files {
-            if self.files.get(path).is_none() || self.files.get(path).unwrap() != hash {
+        for (path, digest) in &b.files {
+            let changed = match self.files.get(path) {
+                None => true,
+                Some(previous) if previous.algorithm == digest.algorithm => {
+                    previous.hash != digest.hash
+                }
+                Some(previous) => {
+                    // `b` was (re)computed with a different algorithm than
+                    // last time; re-hash the file with the previous
+                    // algorithm instead of treating the tag mismatch itself
+                    // as a change and re-uploading the whole tree.
+                    match hash_file(b.directory.join(path), previous.algorithm) {
+                        Ok(hash) => hash != previous.hash,
+                        Err(_) => true,
+                    }
+                }
+            };
+
+            if changed {
                 changed_files.push(path.to_string());
             }
         }
@@ -148,8 +285,8 @@ impl Index {
     pub fn merge(&self, b: &Index) -> Index {
         let mut index = self.clone();
 
-        for (file, hash) in b.files() {
-            index.files.insert(file, hash);
+        for (file, digest) in b.files() {
+            index.files.insert(file, digest);
         }
 
         index
@@ -170,14 +307,94 @@ impl Index {
         self.directory.clone()
     }
 
-    pub fn files(&self) -> HashMap<String, String> {
+    pub fn files(&self) -> HashMap<String, FileDigest> {
         self.files.clone()
     }
+
+    /// Record that `remote_path` is currently being uploaded, tagged with
+    /// the digest of the local content being sent, so an interrupted run
+    /// leaves a trail a fresh one can pick up with a REST-based resume
+    /// instead of re-uploading from scratch -- but only once it has
+    /// confirmed (via that digest) that the local file hasn't changed
+    /// since.
+    pub fn mark_in_progress(
+        &self,
+        remote_path: &str,
+        digest: &FileDigest,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.directory.join(SWAP_FILE))?;
+
+        writeln!(
+            file,
+            "{}{}:{}:{}",
+            RESUME_PREFIX,
+            remote_path,
+            digest.algorithm.as_str(),
+            digest.hash
+        )
+        .map_err(|e| e.into())
+    }
+
+    /// Clear the in-progress marker for `remote_path` once its upload has
+    /// completed.
+    pub fn clear_in_progress(&self, remote_path: &str) -> Result<(), Box<dyn Error>> {
+        let swap_path = self.directory.join(SWAP_FILE);
+        if !swap_path.exists() {
+            return Ok(());
+        }
+
+        let marker_prefix = format!("{}{}:", RESUME_PREFIX, remote_path);
+        let remaining: Vec<String> = BufReader::new(File::open(&swap_path)?)
+            .lines()
+            .filter_map(|l| l.ok())
+            .filter(|l| !l.starts_with(&marker_prefix))
+            .collect();
+
+        // keep every line newline-terminated so a marker appended later by
+        // `mark_in_progress` starts on a line of its own
+        let mut content = remaining.join("\n");
+        if !content.is_empty() {
+            content.push('\n');
+        }
+
+        fs::write(swap_path, content).map_err(|e| e.into())
+    }
+
+    /// Look up the remote path and digest (if any) left mid-upload by an
+    /// interrupted run targeting `directory`. The digest lets a caller gate
+    /// a resume on the local file still matching what was being uploaded,
+    /// rather than grafting new bytes onto a stale partial upload.
+    pub fn resume_marker<P: AsRef<Path>>(directory: P) -> Option<(String, FileDigest)> {
+        let swap_path = directory.as_ref().join(SWAP_FILE);
+        let file = File::open(swap_path).ok()?;
+
+        BufReader::new(file)
+            .lines()
+            .filter_map(|l| l.ok())
+            .find_map(|line| {
+                let rest = line.strip_prefix(RESUME_PREFIX)?;
+                let parts: Vec<&str> = rest.splitn(3, ':').collect();
+                if parts.len() != 3 {
+                    return None;
+                }
+
+                Some((
+                    parts[0].to_string(),
+                    FileDigest {
+                        algorithm: parts[1].parse().ok()?,
+                        hash: parts[2].to_string(),
+                    },
+                ))
+            })
+    }
 }
 
-/// Allows you to access the index file directory with `[]`
+/// Allows you to access the hash of a given path with `[]`
 impl<'a> std::ops::Index<&'a str> for Index {
-    type Output = String;
+    type Output = FileDigest;
 
     fn index(&self, index: &'a str) -> &Self::Output {
         &self.files[index]
@@ -190,7 +407,7 @@ mod tests {
 
     use tempdir::TempDir;
 
-    use crate::index::{Index, IGNORE_FILE, INDEX_FILE, SWAP_FILE};
+    use crate::index::{Algorithm, FileDigest, Index, IGNORE_FILE, INDEX_FILE, SWAP_FILE};
 
     #[test]
     fn test_blank() {
@@ -213,7 +430,8 @@ mod tests {
 
         let (index, _) = Index::load(dir).expect("unable to load index");
         assert_eq!(index.len(), 1);
-        assert_eq!(index["test"], "5d41402abc4b2a76b9719d911017c592");
+        assert_eq!(index["test"].hash, "5d41402abc4b2a76b9719d911017c592");
+        assert_eq!(index["test"].algorithm, Algorithm::Sha1);
     }
 
     #[test]
@@ -234,7 +452,7 @@ mod tests {
         let (index, _) = Index::compute(&dir).expect("unable to compute index");
         assert_eq!(index.len(), 1);
         assert_eq!(index.is_empty(), false);
-        assert_eq!(index["test"], "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d");
+        assert_eq!(index["test"].hash, "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d");
 
         // create a .osyncignore
         fs::write(dir.path().join(IGNORE_FILE), "test\n").expect("unable to write ignore file");
@@ -245,6 +463,51 @@ mod tests {
         assert_eq!(ignored, 4);
     }
 
+    #[test]
+    fn test_compute_with_algorithm_blake3() {
+        let dir = TempDir::new("osync").expect("unable to create temp dir");
+
+        fs::write(dir.path().join("test"), "hello").expect("unable to write test file");
+
+        let (index, _) =
+            Index::compute_with_algorithm(&dir, Algorithm::Blake3).expect("unable to compute index");
+        assert_eq!(index.len(), 1);
+        assert_eq!(index["test"].algorithm, Algorithm::Blake3);
+        assert_eq!(index["test"].hash.len(), 64);
+    }
+
+    #[test]
+    fn test_diff_algorithm_mismatch_same_content_is_not_changed() {
+        let dir = TempDir::new("osync").expect("unable to create temp dir");
+
+        fs::write(dir.path().join("test"), "hello").expect("unable to write test file");
+
+        // previous index was computed with SHA-1
+        let (previous_index, _) = Index::compute(&dir).expect("unable to compute index");
+        // current index is (re)computed with BLAKE3, but the file is unchanged
+        let (current_index, _) = Index::compute_with_algorithm(&dir, Algorithm::Blake3)
+            .expect("unable to compute index");
+
+        let (changed_files, _) = previous_index.diff(&current_index);
+        assert!(changed_files.is_empty());
+    }
+
+    #[test]
+    fn test_diff_algorithm_mismatch_different_content_is_changed() {
+        let dir = TempDir::new("osync").expect("unable to create temp dir");
+
+        fs::write(dir.path().join("test"), "hello").expect("unable to write test file");
+        let (previous_index, _) = Index::compute(&dir).expect("unable to compute index");
+
+        // the file actually changed in between the two runs
+        fs::write(dir.path().join("test"), "goodbye").expect("unable to write test file");
+        let (current_index, _) = Index::compute_with_algorithm(&dir, Algorithm::Blake3)
+            .expect("unable to compute index");
+
+        let (changed_files, _) = previous_index.diff(&current_index);
+        assert_eq!(changed_files, vec!["test".to_string()]);
+    }
+
     #[test]
     fn test_diff() {
         let dir = TempDir::new("osync").expect("unable to create temp dir");
@@ -269,20 +532,35 @@ mod tests {
     #[test]
     fn test_merge() {
         let mut a = Index::blank("");
-        a.files
-            .insert("Test/a.png".to_string(), "Test/a.png.a".to_string());
-        a.files
-            .insert("Test/b.png".to_string(), "Test/b.png.a".to_string());
+        a.files.insert(
+            "Test/a.png".to_string(),
+            FileDigest {
+                algorithm: Algorithm::Sha1,
+                hash: "Test/a.png.a".to_string(),
+            },
+        );
+        a.files.insert(
+            "Test/b.png".to_string(),
+            FileDigest {
+                algorithm: Algorithm::Sha1,
+                hash: "Test/b.png.a".to_string(),
+            },
+        );
 
         let mut b = Index::blank("");
-        b.files
-            .insert("Test/b.png".to_string(), "Test/b.png.b".to_string());
+        b.files.insert(
+            "Test/b.png".to_string(),
+            FileDigest {
+                algorithm: Algorithm::Sha1,
+                hash: "Test/b.png.b".to_string(),
+            },
+        );
 
         let result = a.merge(&b);
         assert_eq!(result.len(), 2);
 
-        assert_eq!(result["Test/a.png"], "Test/a.png.a");
-        assert_eq!(result["Test/b.png"], "Test/b.png.b");
+        assert_eq!(result["Test/a.png"].hash, "Test/a.png.a");
+        assert_eq!(result["Test/b.png"].hash, "Test/b.png.b");
     }
 
     #[test]